@@ -8,13 +8,16 @@ impl CompactExt for SkiplistEngine {
         Ok(true)
     }
 
+    /// A no-op: `SyncMutable::delete_cf`/`delete_range_cf` already remove
+    /// entries from the skiplist outright, so there is nothing left behind
+    /// in `[start_key, end_key)` for a compaction to reclaim.
     fn compact_range(
         &self,
-        cf: &str,
-        start_key: Option<&[u8]>,
-        end_key: Option<&[u8]>,
-        exclusive_manual: bool,
-        max_subcompactions: u32,
+        _cf: &str,
+        _start_key: Option<&[u8]>,
+        _end_key: Option<&[u8]>,
+        _exclusive_manual: bool,
+        _max_subcompactions: u32,
     ) -> Result<()> {
         Ok(())
     }
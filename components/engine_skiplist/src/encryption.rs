@@ -0,0 +1,196 @@
+// Copyright 2020 TiKV Project Authors. Licensed under Apache-2.0.
+
+//! Optional encryption-at-rest for raft log entries and `RaftLocalState`,
+//! mirroring how the rest of the store already gets its data encryption
+//! keys from `encryption::DataKeyManager` rather than managing key
+//! material itself.
+//!
+//! Each raft group is treated as one logical "file" in the key manager's
+//! dictionary, so it gets one data key for its whole lifetime. Every
+//! value is then encrypted independently with AES-256-CTR under that key,
+//! with the per-value nonce built from the group's base IV, the entry's
+//! log index, and a fresh random salt stored alongside the ciphertext -
+//! so no two values ever reuse the same keystream even though they share
+//! a key.
+
+use std::io;
+use std::sync::Arc;
+
+use dashmap::DashMap;
+use encryption::{DataKeyManager, FileEncryptionInfo};
+use openssl::rand::rand_bytes;
+use openssl::symm::{Cipher, Crypter, Mode};
+
+/// Marks a value as `[scheme][salt][ciphertext]` produced by
+/// [`RaftEncryption::encrypt`]. Values whose first byte doesn't match any
+/// known scheme are assumed to predate encryption being enabled and are
+/// passed through unchanged.
+const SCHEME_AES_256_CTR: u8 = 0xe1;
+const SALT_LEN: usize = 8;
+
+fn crypto_err(e: impl std::fmt::Display) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, e.to_string())
+}
+
+/// Per-group AES-256-CTR encryption for raft log values, backed by a
+/// shared `DataKeyManager`.
+pub struct RaftEncryption {
+    key_manager: Arc<DataKeyManager>,
+    keys: DashMap<u64, FileEncryptionInfo>,
+}
+
+impl std::fmt::Debug for RaftEncryption {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("RaftEncryption")
+            .field("groups", &self.keys.len())
+            .finish()
+    }
+}
+
+impl RaftEncryption {
+    pub fn new(key_manager: Arc<DataKeyManager>) -> RaftEncryption {
+        RaftEncryption {
+            key_manager,
+            keys: DashMap::new(),
+        }
+    }
+
+    /// Synthesizes a stable "file name" for `raft_group_id`'s data key, so
+    /// it's tracked in the same key dictionary as SST/WAL files.
+    fn file_name(raft_group_id: u64) -> String {
+        format!("raft-log/{:016x}", raft_group_id)
+    }
+
+    fn key_for_group(&self, raft_group_id: u64) -> io::Result<FileEncryptionInfo> {
+        if let Some(info) = self.keys.get(&raft_group_id) {
+            return Ok(info.clone());
+        }
+        let fname = Self::file_name(raft_group_id);
+        let info = match self.key_manager.get_file(&fname) {
+            Ok(info) => info,
+            Err(_) => self.key_manager.new_file(&fname).map_err(crypto_err)?,
+        };
+        self.keys.insert(raft_group_id, info.clone());
+        Ok(info)
+    }
+
+    /// Encrypts `plaintext` for `raft_group_id`, returning
+    /// `[scheme][salt][ciphertext]`. `index` is the raft log index the
+    /// value belongs to, or `0` for values with no natural index (e.g.
+    /// `RaftLocalState`) - the random salt already guarantees a fresh
+    /// keystream per call on its own.
+    pub fn encrypt(&self, raft_group_id: u64, index: u64, plaintext: &[u8]) -> io::Result<Vec<u8>> {
+        let info = self.key_for_group(raft_group_id)?;
+        encrypt_with_key(&info.key, &info.iv, index, plaintext)
+    }
+
+    /// Decrypts a value produced by [`encrypt`](Self::encrypt). A value
+    /// that isn't tagged with a recognized scheme byte is returned as-is,
+    /// so enabling encryption on an existing store is non-destructive for
+    /// values written before it was turned on.
+    pub fn decrypt(&self, raft_group_id: u64, index: u64, value: &[u8]) -> io::Result<Vec<u8>> {
+        if is_legacy_plaintext(value) {
+            return Ok(value.to_vec());
+        }
+        let info = self.key_for_group(raft_group_id)?;
+        decrypt_with_key(&info.key, &info.iv, index, value)
+    }
+}
+
+/// Whether `value` predates encryption being enabled: anything not tagged
+/// with a recognized scheme byte, or too short to hold one.
+fn is_legacy_plaintext(value: &[u8]) -> bool {
+    value.first() != Some(&SCHEME_AES_256_CTR) || value.len() < 1 + SALT_LEN
+}
+
+/// Combines a group's base IV with `index` and `salt` into the per-value
+/// CTR nonce.
+fn nonce(iv: &[u8], index: u64, salt: &[u8]) -> Vec<u8> {
+    let mut nonce = iv.to_vec();
+    for (i, b) in index.to_be_bytes().iter().enumerate() {
+        nonce[i] ^= b;
+    }
+    for (i, b) in salt.iter().enumerate() {
+        nonce[SALT_LEN + i] ^= b;
+    }
+    nonce
+}
+
+/// The actual AES-256-CTR work behind [`RaftEncryption::encrypt`], pulled
+/// out so it can be tested without a real `DataKeyManager` to source a
+/// `FileEncryptionInfo` from.
+fn encrypt_with_key(key: &[u8], iv: &[u8], index: u64, plaintext: &[u8]) -> io::Result<Vec<u8>> {
+    let mut salt = [0u8; SALT_LEN];
+    rand_bytes(&mut salt).map_err(crypto_err)?;
+    let nonce = nonce(iv, index, &salt);
+
+    let cipher = Cipher::aes_256_ctr();
+    let mut crypter = Crypter::new(cipher, Mode::Encrypt, key, Some(&nonce)).map_err(crypto_err)?;
+    let mut ciphertext = vec![0u8; plaintext.len() + cipher.block_size()];
+    let mut len = crypter.update(plaintext, &mut ciphertext).map_err(crypto_err)?;
+    len += crypter.finalize(&mut ciphertext[len..]).map_err(crypto_err)?;
+    ciphertext.truncate(len);
+
+    let mut out = Vec::with_capacity(1 + SALT_LEN + ciphertext.len());
+    out.push(SCHEME_AES_256_CTR);
+    out.extend_from_slice(&salt);
+    out.extend_from_slice(&ciphertext);
+    Ok(out)
+}
+
+/// The actual AES-256-CTR work behind [`RaftEncryption::decrypt`], pulled
+/// out for the same reason as [`encrypt_with_key`]. Callers are expected to
+/// have already ruled out [`is_legacy_plaintext`].
+fn decrypt_with_key(key: &[u8], iv: &[u8], index: u64, value: &[u8]) -> io::Result<Vec<u8>> {
+    let salt = &value[1..1 + SALT_LEN];
+    let ciphertext = &value[1 + SALT_LEN..];
+    let nonce = nonce(iv, index, salt);
+
+    let cipher = Cipher::aes_256_ctr();
+    let mut crypter = Crypter::new(cipher, Mode::Decrypt, key, Some(&nonce)).map_err(crypto_err)?;
+    let mut plaintext = vec![0u8; ciphertext.len() + cipher.block_size()];
+    let mut len = crypter.update(ciphertext, &mut plaintext).map_err(crypto_err)?;
+    len += crypter.finalize(&mut plaintext[len..]).map_err(crypto_err)?;
+    plaintext.truncate(len);
+    Ok(plaintext)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encrypt_decrypt_round_trip() {
+        let key = vec![7u8; 32];
+        let iv = vec![3u8; 16];
+        let plaintext = b"hello raft log entry";
+
+        let encrypted = encrypt_with_key(&key, &iv, 42, plaintext).unwrap();
+        assert!(!is_legacy_plaintext(&encrypted));
+        assert_ne!(encrypted, plaintext.to_vec());
+
+        let decrypted = decrypt_with_key(&key, &iv, 42, &encrypted).unwrap();
+        assert_eq!(decrypted, plaintext.to_vec());
+    }
+
+    #[test]
+    fn test_decrypt_wrong_index_does_not_round_trip() {
+        let key = vec![7u8; 32];
+        let iv = vec![3u8; 16];
+        let plaintext = b"hello raft log entry";
+
+        let encrypted = encrypt_with_key(&key, &iv, 42, plaintext).unwrap();
+        let decrypted = decrypt_with_key(&key, &iv, 43, &encrypted).unwrap();
+        assert_ne!(decrypted, plaintext.to_vec());
+    }
+
+    #[test]
+    fn test_legacy_plaintext_is_passed_through_unchanged() {
+        // A value written before encryption was ever enabled has no
+        // `SCHEME_AES_256_CTR` tag and must be recognized as such rather
+        // than misread as ciphertext.
+        assert!(is_legacy_plaintext(b"plain raft log value"));
+        assert!(is_legacy_plaintext(&[]));
+        assert!(is_legacy_plaintext(&[SCHEME_AES_256_CTR]));
+    }
+}
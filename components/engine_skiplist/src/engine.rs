@@ -1,7 +1,8 @@
 // Copyright 2019 TiKV Project Authors. Licensed under Apache-2.0.
 
 use std::ops::{Bound, Range};
-use std::sync::Arc;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, Weak};
 
 use crossbeam_skiplist::map::{Entry as SkipEntry, Range as SkipRange, SkipMap};
 use engine_traits::{
@@ -10,21 +11,38 @@ use engine_traits::{
 };
 use std::sync::atomic::{AtomicUsize, Ordering};
 use tikv_util::collections::HashMap;
+use tikv_util::warn;
+
+use encryption::DataKeyManager;
 
 use crate::cf_handle::SkiplistCFHandle;
 use crate::db_vector::SkiplistDBVector;
+use crate::encryption::RaftEncryption;
+use crate::eviction::{Eviction, EvictionPolicy};
+use crate::index_cache::IndexCache;
 use crate::snapshot::SkiplistSnapshot;
+use crate::wal::Wal;
 use crate::write_batch::SkiplistWriteBatch;
 
 static ENGINE_SEQ_NO_ALLOC: AtomicUsize = AtomicUsize::new(0);
 
 pub struct SkiplistEngineBuilder {
     cf_names: Vec<CfName>,
+    wal_dir: Option<PathBuf>,
+    key_manager: Option<Arc<DataKeyManager>>,
+    capacity_bytes: Option<usize>,
+    eviction_policy: EvictionPolicy,
 }
 
 impl SkiplistEngineBuilder {
     pub fn new() -> Self {
-        Self { cf_names: vec![] }
+        Self {
+            cf_names: vec![],
+            wal_dir: None,
+            key_manager: None,
+            capacity_bytes: None,
+            eviction_policy: EvictionPolicy::Fifo,
+        }
     }
 
     pub fn cf_names(mut self, names: &[CfName]) -> Self {
@@ -32,6 +50,42 @@ impl SkiplistEngineBuilder {
         self
     }
 
+    /// Backs the engine with a segmented WAL rooted at `dir`, giving it
+    /// real durability across restarts. Without this the engine stays pure
+    /// in-memory, as before. Existing segments under `dir` are replayed
+    /// into the skiplist before `build` returns.
+    pub fn wal_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.wal_dir = Some(dir.into());
+        self
+    }
+
+    /// Encrypts raft log entries and `RaftLocalState` at rest using the
+    /// same data key manager the rest of the store passes down for its
+    /// encrypted SST/WAL files. Without this the engine stores plaintext,
+    /// as before.
+    pub fn key_manager(mut self, key_manager: Arc<DataKeyManager>) -> Self {
+        self.key_manager = Some(key_manager);
+        self
+    }
+
+    /// Enforces `total_bytes` as a memory budget: once a `put_cf` pushes it
+    /// past `capacity_bytes`, entries of the written cf are evicted (per
+    /// `eviction_policy`, [`EvictionPolicy::Fifo`] by default) until it
+    /// fits again or that cf is empty. While a `SkiplistSnapshot` is
+    /// outstanding, eviction holds off until `total_bytes` exceeds twice
+    /// `capacity_bytes`. Without this the engine grows unbounded, as before.
+    pub fn capacity_bytes(mut self, capacity_bytes: usize) -> Self {
+        self.capacity_bytes = Some(capacity_bytes);
+        self
+    }
+
+    /// Selects which entries `capacity_bytes` eviction picks first. Has no
+    /// effect unless `capacity_bytes` is also set.
+    pub fn eviction_policy(mut self, policy: EvictionPolicy) -> Self {
+        self.eviction_policy = policy;
+        self
+    }
+
     pub fn build(self) -> SkiplistEngine {
         let mut engines = HashMap::default();
         let mut cf_handles = HashMap::default();
@@ -54,11 +108,33 @@ impl SkiplistEngineBuilder {
                 cf_handles.insert(cf_name, cf_handle);
             }
         }
-        SkiplistEngine {
+        let wal = self.wal_dir.as_ref().and_then(|dir| match Wal::open(dir) {
+            Ok(wal) => Some(Arc::new(wal)),
+            Err(e) => {
+                warn!("failed to open raft engine WAL, falling back to memory-only";
+                    "dir" => ?dir, "err" => %e);
+                None
+            }
+        });
+        let engine = SkiplistEngine {
             engines,
             cf_handles,
             total_bytes: Arc::new(AtomicUsize::new(0)),
+            wal,
+            index_cache: Arc::new(IndexCache::new()),
+            encryption: self.key_manager.map(RaftEncryption::new).map(Arc::new),
+            eviction: self
+                .capacity_bytes
+                .map(|cap| Arc::new(Eviction::new(cap, self.eviction_policy))),
+            live_snapshots: Arc::new(Mutex::new(Vec::new())),
+            snapshot_marker: None,
+        };
+        if let Some(wal) = &engine.wal {
+            if let Err(e) = wal.replay(&engine) {
+                warn!("failed to replay raft engine WAL"; "err" => %e);
+            }
         }
+        engine
     }
 }
 
@@ -67,6 +143,27 @@ pub struct SkiplistEngine {
     pub total_bytes: Arc<AtomicUsize>,
     pub(crate) engines: HashMap<SkiplistCFHandle, Arc<SkipMap<Vec<u8>, Vec<u8>>>>,
     pub(crate) cf_handles: HashMap<CfName, SkiplistCFHandle>,
+    pub(crate) wal: Option<Arc<Wal>>,
+    /// Cached first/last live log index per raft group, consulted by
+    /// `RaftEngine::gc`/`clean`/`fetch_entries_to` to avoid seeking the
+    /// skiplist. Kept up to date from the same `put_cf`/`delete_cf` paths
+    /// that mutate raft log entries.
+    pub(crate) index_cache: Arc<IndexCache>,
+    /// Per-group AES-256-CTR encryption for raft log values. `None` means
+    /// encryption at rest isn't enabled and values are stored plaintext.
+    pub(crate) encryption: Option<Arc<RaftEncryption>>,
+    /// Enforces `capacity_bytes` by evicting entries on `put_cf`. `None`
+    /// means `total_bytes` is tracked but never acted on, as before.
+    pub(crate) eviction: Option<Arc<Eviction>>,
+    /// Every outstanding `SkiplistSnapshot`'s liveness marker, so eviction
+    /// can tell whether it's safe to run at all. Shared by every clone of
+    /// this engine; entries are weak so a dropped snapshot's slot goes
+    /// stale on its own.
+    pub(crate) live_snapshots: Arc<Mutex<Vec<Weak<()>>>>,
+    /// Set only on the `SkiplistEngine` clone handed to a `SkiplistSnapshot`
+    /// - keeps that snapshot's entry in `live_snapshots` alive for exactly
+    /// as long as the snapshot (and its stored engine clone) exists.
+    pub(crate) snapshot_marker: Option<Arc<()>>,
 }
 
 impl SkiplistEngine {
@@ -79,13 +176,30 @@ impl SkiplistEngine {
             .get(handle)
             .ok_or_else(|| Error::Engine("cannot get engine by handle".to_string()))
     }
+
+    /// Whether any `SkiplistSnapshot` taken from this engine is still
+    /// alive. Consulted by eviction, which has no way to tell which keys a
+    /// given snapshot still needs, so it conservatively holds off while any
+    /// snapshot is alive, up to twice its `capacity_bytes` budget.
+    pub(crate) fn has_live_snapshot(&self) -> bool {
+        let mut markers = self.live_snapshots.lock().unwrap();
+        markers.retain(|m| m.upgrade().is_some());
+        !markers.is_empty()
+    }
 }
 
 impl KvEngine for SkiplistEngine {
     type Snapshot = SkiplistSnapshot;
 
     fn snapshot(&self) -> Self::Snapshot {
-        SkiplistSnapshot::new(self.clone())
+        let marker = Arc::new(());
+        self.live_snapshots
+            .lock()
+            .unwrap()
+            .push(Arc::downgrade(&marker));
+        let mut snap_engine = self.clone();
+        snap_engine.snapshot_marker = Some(marker);
+        SkiplistSnapshot::new(snap_engine)
     }
     fn sync(&self) -> Result<()> {
         Ok(())
@@ -108,9 +222,13 @@ impl Peekable for SkiplistEngine {
         key: &[u8],
     ) -> Result<Option<Self::DBVector>> {
         let engine = self.get_cf_engine(cf)?;
-        Ok(engine
-            .get(key)
-            .map(|e| SkiplistDBVector(e.value().to_vec())))
+        let value = engine.get(key).map(|e| SkiplistDBVector(e.value().to_vec()));
+        if value.is_some() {
+            if let Some(eviction) = &self.eviction {
+                eviction.note_access(cf, key);
+            }
+        }
+        Ok(value)
     }
 }
 
@@ -119,10 +237,18 @@ impl SyncMutable for SkiplistEngine {
         self.put_cf(CF_DEFAULT, key, value)
     }
     fn put_cf(&self, cf: &str, key: &[u8], value: &[u8]) -> Result<()> {
+        if let Some(wal) = &self.wal {
+            box_try!(wal.append_put(cf, key, value));
+        }
         self.total_bytes.fetch_add(key.len(), Ordering::Relaxed);
         self.total_bytes.fetch_add(value.len(), Ordering::Relaxed);
         let engine = self.get_cf_engine(cf)?;
         engine.insert(key.to_vec(), value.to_vec());
+        self.index_cache.note_put(key);
+        if let Some(eviction) = &self.eviction {
+            eviction.note_write(cf, key);
+            eviction.evict_to_fit(self, cf);
+        }
         Ok(())
     }
 
@@ -130,12 +256,24 @@ impl SyncMutable for SkiplistEngine {
         self.delete_cf(CF_DEFAULT, key)
     }
     fn delete_cf(&self, cf: &str, key: &[u8]) -> Result<()> {
+        if let Some(wal) = &self.wal {
+            box_try!(wal.append_delete(cf, key));
+        }
         let engine = self.get_cf_engine(cf)?;
         if let Some(e) = engine.remove(key) {
             self.total_bytes.fetch_sub(e.key().len(), Ordering::Relaxed);
             self.total_bytes
                 .fetch_sub(e.value().len(), Ordering::Relaxed);
         }
+        // Deliberately not `index_cache.note_delete(key)` here: `gc` and
+        // `cut_logs` delete one key at a time through this exact path and
+        // already update the cache themselves once they know the real new
+        // boundary (see `index_cache::IndexCache::note_delete`'s doc
+        // comment). `delete_range_cf` below still invalidates generically
+        // for deletes that don't go through `gc`/`cut_logs`.
+        if let Some(eviction) = &self.eviction {
+            eviction.note_removed(cf, key);
+        }
         Ok(())
     }
     fn delete_range_cf(&self, cf: &str, begin_key: &[u8], end_key: &[u8]) -> Result<()> {
@@ -144,12 +282,28 @@ impl SyncMutable for SkiplistEngine {
             end: end_key.to_vec(),
         };
         let engine = self.get_cf_engine(cf)?;
-        engine.range(range).for_each(|e| {
-            e.remove();
-            self.total_bytes.fetch_sub(e.key().len(), Ordering::Relaxed);
-            self.total_bytes
-                .fetch_sub(e.value().len(), Ordering::Relaxed);
-        });
+        let keys: Vec<Vec<u8>> = engine.range(range).map(|e| e.key().clone()).collect();
+        // Append every WAL record before removing anything from memory, the
+        // same order `put_cf`/`delete_cf` use: if an append fails partway
+        // through, `box_try!` bails here with nothing yet removed, so
+        // memory and WAL can't diverge the way they would if we kept going
+        // and just warned.
+        if let Some(wal) = &self.wal {
+            for key in &keys {
+                box_try!(wal.append_delete(cf, key));
+            }
+        }
+        for key in &keys {
+            if let Some(e) = engine.remove(key) {
+                self.index_cache.note_delete(e.key());
+                if let Some(eviction) = &self.eviction {
+                    eviction.note_removed(cf, e.key());
+                }
+                self.total_bytes.fetch_sub(e.key().len(), Ordering::Relaxed);
+                self.total_bytes
+                    .fetch_sub(e.value().len(), Ordering::Relaxed);
+            }
+        }
         Ok(())
     }
 }
@@ -0,0 +1,236 @@
+// Copyright 2020 TiKV Project Authors. Licensed under Apache-2.0.
+
+//! Pluggable eviction for `SkiplistEngine` once its tracked `total_bytes`
+//! crosses a configured `capacity_bytes` budget. `SkiplistEngine` is pure
+//! in-memory, so without this a write-heavy workload grows the skiplist
+//! without bound.
+//!
+//! Two policies are selectable at build time via
+//! `SkiplistEngineBuilder::capacity_bytes`/`eviction_policy`:
+//! - [`EvictionPolicy::Fifo`] sweeps a cf from its lowest key, approximating
+//!   insertion order with sort order since the skiplist itself keeps no
+//!   separate insertion log.
+//! - [`EvictionPolicy::Lru`] tracks a per-key access tick (bumped on every
+//!   `get_value_cf_opt`, seeded on every write so a key that's never read is
+//!   tracked too) in a `(cf, key) -> tick` side table plus its reverse,
+//!   per-cf ordered `(tick, key)` index, so the coldest key in a cf is found with a
+//!   single lookup instead of a scan over the whole cf.
+//!
+//! Either way, eviction holds off past `capacity_bytes` while any
+//! `SkiplistSnapshot` is outstanding, since this engine has no versioning to
+//! let eviction and an in-progress snapshot read safely disagree about
+//! what's still there - but only up to [`SNAPSHOT_GRACE_MULTIPLIER`] times
+//! over budget, so a long-lived snapshot can't block eviction forever and
+//! let memory grow unbounded.
+
+use std::collections::BTreeSet;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+use dashmap::DashMap;
+use tikv_util::collections::HashMap;
+
+use crate::engine::SkiplistEngine;
+
+// Starts at 1 so every real access tick sorts after `note_write`'s 0 seed.
+static ACCESS_TICK_ALLOC: AtomicU64 = AtomicU64::new(1);
+
+fn next_tick() -> usize {
+    ACCESS_TICK_ALLOC.fetch_add(1, Ordering::Relaxed) as usize
+}
+
+/// While any `SkiplistSnapshot` is outstanding, eviction is allowed to defer
+/// past `capacity_bytes` up to this multiple of it before running anyway.
+const SNAPSHOT_GRACE_MULTIPLIER: usize = 2;
+
+/// Eviction policy selectable via
+/// `SkiplistEngineBuilder::eviction_policy`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EvictionPolicy {
+    /// Sweeps a cf from its lowest key until enough bytes are freed.
+    Fifo,
+    /// Evicts whichever key has gone longest without a
+    /// `get_value_cf_opt`.
+    Lru,
+}
+
+#[derive(Debug)]
+pub struct Eviction {
+    capacity_bytes: usize,
+    policy: EvictionPolicy,
+    /// Current access tick per `(cf, key)`, maintained only under
+    /// [`EvictionPolicy::Lru`] - for every live key, not just ones that
+    /// have actually been read, so `pick_victim` never needs to fall back
+    /// to scanning the cf itself.
+    access: DashMap<(String, Vec<u8>), usize>,
+    /// `access`'s reverse index: one ordered `(tick, key)` set per cf, kept
+    /// in sync with `access` under the same lock, so the coldest key in a cf
+    /// is a single `BTreeSet::iter().next()` away. Keys sharing a tick (every
+    /// never-read key sits at `0`) are ordered by key.
+    by_tick: Mutex<HashMap<String, BTreeSet<(usize, Vec<u8>)>>>,
+}
+
+impl Eviction {
+    pub fn new(capacity_bytes: usize, policy: EvictionPolicy) -> Eviction {
+        Eviction {
+            capacity_bytes,
+            policy,
+            access: DashMap::new(),
+            by_tick: Mutex::new(HashMap::default()),
+        }
+    }
+
+    /// Bumps `key`'s access tick. A no-op under [`EvictionPolicy::Fifo`],
+    /// which doesn't track access recency.
+    pub fn note_access(&self, cf: &str, key: &[u8]) {
+        if self.policy != EvictionPolicy::Lru {
+            return;
+        }
+        self.set_tick(cf, key, next_tick());
+    }
+
+    /// Registers a freshly written key, so it's visible to `pick_victim`
+    /// even if it's never read through `get_value_cf_opt` - a write-only
+    /// key would otherwise have no entry in `access`/`by_tick` at all,
+    /// making it invisible to (and so never chosen by) eviction. Seeded at
+    /// tick `0` - lower than any real access tick - so it's the coldest key
+    /// until something actually reads it. A no-op if `key` is already
+    /// tracked (an overwrite shouldn't reset its recency) or under
+    /// [`EvictionPolicy::Fifo`].
+    pub fn note_write(&self, cf: &str, key: &[u8]) {
+        if self.policy != EvictionPolicy::Lru {
+            return;
+        }
+        if self.access.contains_key(&(cf.to_owned(), key.to_vec())) {
+            return;
+        }
+        self.set_tick(cf, key, 0);
+    }
+
+    fn set_tick(&self, cf: &str, key: &[u8], tick: usize) {
+        let k = (cf.to_owned(), key.to_vec());
+        let old_tick = self.access.get(&k).map(|e| *e);
+        let mut by_tick = self.by_tick.lock().unwrap();
+        if let Some(old_tick) = old_tick {
+            if let Some(ticks) = by_tick.get_mut(cf) {
+                ticks.remove(&(old_tick, key.to_vec()));
+            }
+        }
+        by_tick
+            .entry(cf.to_owned())
+            .or_insert_with(BTreeSet::new)
+            .insert((tick, key.to_vec()));
+        drop(by_tick);
+        self.access.insert(k, tick);
+    }
+
+    /// Forgets `key`'s access tick once it leaves the skiplist, however
+    /// that happened (eviction, a regular delete, or a GC/compaction).
+    pub fn note_removed(&self, cf: &str, key: &[u8]) {
+        if self.policy != EvictionPolicy::Lru {
+            return;
+        }
+        if let Some((_, tick)) = self.access.remove(&(cf.to_owned(), key.to_vec())) {
+            if let Some(ticks) = self.by_tick.lock().unwrap().get_mut(cf) {
+                ticks.remove(&(tick, key.to_vec()));
+            }
+        }
+    }
+
+    fn pick_victim(&self, engine: &SkiplistEngine, cf: &str) -> Option<Vec<u8>> {
+        match self.policy {
+            EvictionPolicy::Fifo => {
+                let map = engine.get_cf_engine(cf).ok()?;
+                map.iter().next().map(|e| e.key().clone())
+            }
+            EvictionPolicy::Lru => self
+                .by_tick
+                .lock()
+                .unwrap()
+                .get(cf)
+                .and_then(|ticks| ticks.iter().next().map(|(_, key)| key.clone())),
+        }
+    }
+
+    /// Evicts entries from `cf` until `engine.total_bytes` is back at or
+    /// under `capacity_bytes`. While `engine` has a live `SkiplistSnapshot`
+    /// outstanding, holds off until `total_bytes` crosses
+    /// `capacity_bytes * SNAPSHOT_GRACE_MULTIPLIER` instead, so a long-lived
+    /// snapshot can't pin the budget open forever.
+    pub fn evict_to_fit(&self, engine: &SkiplistEngine, cf: &str) {
+        let hard_limit = self.capacity_bytes.saturating_mul(SNAPSHOT_GRACE_MULTIPLIER);
+        loop {
+            let total = engine.total_bytes.load(Ordering::Relaxed);
+            if total <= self.capacity_bytes {
+                return;
+            }
+            if total <= hard_limit && engine.has_live_snapshot() {
+                return;
+            }
+            let key = match self.pick_victim(engine, cf) {
+                Some(key) => key,
+                None => return, // cf is empty; nothing left to reclaim
+            };
+            let map = match engine.get_cf_engine(cf) {
+                Ok(map) => map,
+                Err(_) => return,
+            };
+            if let Some(e) = map.remove(&key) {
+                engine
+                    .total_bytes
+                    .fetch_sub(e.key().len() + e.value().len(), Ordering::Relaxed);
+            }
+            self.note_removed(cf, &key);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use engine_traits::{Peekable, SyncMutable};
+
+    use super::*;
+    use crate::engine::SkiplistEngineBuilder;
+
+    #[test]
+    fn test_fifo_evicts_lowest_key_first() {
+        let engine = SkiplistEngineBuilder::new()
+            .capacity_bytes(16)
+            .eviction_policy(EvictionPolicy::Fifo)
+            .build();
+        engine.put(b"a", b"1111").unwrap();
+        engine.put(b"b", b"2222").unwrap();
+        engine.put(b"c", b"3333").unwrap();
+        engine.put(b"d", b"4444").unwrap();
+
+        assert!(engine.get_value(b"a").unwrap().is_none());
+        assert!(engine.get_value(b"d").unwrap().is_some());
+    }
+
+    #[test]
+    fn test_lru_evicts_never_read_key_before_recently_read_one() {
+        let engine = SkiplistEngineBuilder::new()
+            .capacity_bytes(24)
+            .eviction_policy(EvictionPolicy::Lru)
+            .build();
+
+        // Burn one access tick so "hot"'s real tick below is guaranteed to
+        // be strictly greater than the `0` seed a never-accessed key gets
+        // from `note_write`.
+        engine.put(b"warmup", b"x").unwrap();
+        engine.get_value(b"warmup").unwrap();
+
+        engine.put(b"hot", b"1111").unwrap();
+        engine.put(b"cold", b"2222").unwrap();
+        engine.get_value(b"hot").unwrap();
+
+        // Push past capacity_bytes: "cold" has never been read through
+        // `get_value_cf_opt` and so only has `note_write`'s tick-0 seed in
+        // the access side table. It must be picked as the coldest key, not
+        // "hot".
+        engine.put(b"newest", b"3333").unwrap();
+
+        assert!(engine.get_value(b"cold").unwrap().is_none());
+        assert!(engine.get_value(b"hot").unwrap().is_some());
+    }
+}
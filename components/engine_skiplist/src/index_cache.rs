@@ -0,0 +1,105 @@
+// Copyright 2020 TiKV Project Authors. Licensed under Apache-2.0.
+
+//! An in-memory cache of each raft group's live log index range, so
+//! `gc`/`clean`/`fetch_entries_to` don't have to `seek`/scan the skiplist
+//! just to learn where a group's log currently starts and ends.
+//!
+//! The cache is maintained from the same paths that mutate the log, and a
+//! miss (a group it has never seen) falls back to scanning the skiplist
+//! directly. A cached range is only narrowed by `note_gc` or dropped by
+//! `remove`/`note_delete`; entries removed any other way (a plain
+//! `delete_cf`, eviction, or a delete replayed from the WAL) stay inside
+//! it, so a hit does not prove that every index in the range is present.
+
+use std::convert::TryInto;
+
+use dashmap::DashMap;
+
+/// The inclusive range of log indices a raft group currently has entries
+/// for in the skiplist.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RegionIndexRange {
+    pub first: u64,
+    pub last: u64,
+}
+
+/// Recovers the raft group id from a `keys::raft_log_key(region_id, _)`,
+/// which lays the region id out as a big-endian `u64` right after the two
+/// fixed prefix bytes (`LOCAL_PREFIX`, `REGION_RAFT_PREFIX`).
+fn raft_log_group_id(key: &[u8]) -> Option<u64> {
+    let region_id_bytes = key.get(2..10)?;
+    Some(u64::from_be_bytes(region_id_bytes.try_into().ok()?))
+}
+
+#[derive(Default, Debug)]
+pub struct IndexCache {
+    ranges: DashMap<u64, RegionIndexRange>,
+}
+
+impl IndexCache {
+    pub fn new() -> IndexCache {
+        IndexCache {
+            ranges: DashMap::new(),
+        }
+    }
+
+    pub fn get(&self, raft_group_id: u64) -> Option<RegionIndexRange> {
+        self.ranges.get(&raft_group_id).map(|r| *r.value())
+    }
+
+    /// If `key` is a raft log key, widens that group's cached range to
+    /// cover `key`'s index. No-op for any other key (e.g. a raft state
+    /// key), which just never shows up in the cache.
+    pub fn note_put(&self, key: &[u8]) {
+        let (group, index) = match (raft_log_group_id(key), keys::raft_log_index(key)) {
+            (Some(group), Ok(index)) => (group, index),
+            _ => return,
+        };
+        self.ranges
+            .entry(group)
+            .and_modify(|r| {
+                r.first = r.first.min(index);
+                r.last = r.last.max(index);
+            })
+            .or_insert(RegionIndexRange {
+                first: index,
+                last: index,
+            });
+    }
+
+    /// Records that `gc`/`cut_logs` advanced `raft_group_id`'s first live
+    /// index to `new_first`. The sole source of truth for shrinking a
+    /// group's cached `first`; callers must invoke this themselves after a
+    /// bulk delete rather than relying on `note_delete` to catch it.
+    pub fn note_gc(&self, raft_group_id: u64, new_first: u64) {
+        if let Some(mut r) = self.ranges.get_mut(&raft_group_id) {
+            if new_first > r.last {
+                drop(r);
+                self.ranges.remove(&raft_group_id);
+            } else {
+                r.first = r.first.max(new_first);
+            }
+        }
+    }
+
+    /// Forgets everything cached for a group, e.g. once `clean` removes it
+    /// entirely.
+    pub fn remove(&self, raft_group_id: u64) {
+        self.ranges.remove(&raft_group_id);
+    }
+
+    /// Invalidates the cached range for whichever group `key` belongs to,
+    /// if any. Deliberately *not* called from `gc`/`cut_logs`'s own
+    /// per-key deletes: those already call [`note_gc`](Self::note_gc) once
+    /// they know the real new boundary, and wiping the range out from
+    /// under them on every individual key first would make that call a
+    /// no-op, leaving the group absent for the next `note_put` to reseed
+    /// `first` from whatever index happens to be appended next - which is
+    /// not necessarily the group's true first. Reserved for bulk deletes
+    /// (`delete_range_cf`) that don't otherwise touch the cache at all.
+    pub fn note_delete(&self, key: &[u8]) {
+        if let Some(group) = raft_log_group_id(key) {
+            self.remove(group);
+        }
+    }
+}
@@ -1,16 +1,55 @@
+use std::convert::TryInto;
+
+use crc32c::crc32c;
+
 use crate::{SkiplistEngine, SkiplistWriteBatch};
 
 use engine_traits::{
     Iterable, MiscExt, Mutable, Peekable, SyncMutable, WriteBatch, WriteBatchExt, WriteOptions,
-    CF_DEFAULT, MAX_DELETE_BATCH_SIZE,
+    MAX_DELETE_BATCH_SIZE,
 };
 use kvproto::raft_serverpb::RaftLocalState;
 use protobuf::Message;
 use raft::{eraftpb::Entry, StorageError};
 use raft_engine::{CacheStats, Error, RaftEngine, RaftLogBatch, Result};
+use tikv_util::error;
 
 const RAFT_LOG_MULTI_GET_CNT: u64 = 8;
 
+/// Version byte prepended to a framed entry value, ahead of a 4-byte
+/// crc32c of the serialized `Entry` that follows it. Values written before
+/// this framing existed have neither and are merged from directly, so
+/// enabling the check on an existing store is non-destructive.
+const ENTRY_FRAME_VERSION: u8 = 1;
+const ENTRY_FRAME_HEADER_LEN: usize = 1 + 4;
+
+/// Frames a serialized `Entry` as `[version][crc32c][payload]`.
+fn frame_entry(payload: &[u8]) -> Vec<u8> {
+    let mut framed = Vec::with_capacity(ENTRY_FRAME_HEADER_LEN + payload.len());
+    framed.push(ENTRY_FRAME_VERSION);
+    framed.extend_from_slice(&crc32c(payload).to_le_bytes());
+    framed.extend_from_slice(payload);
+    framed
+}
+
+/// Strips the framing added by [`frame_entry`] and verifies its crc32c.
+/// Values that don't start with [`ENTRY_FRAME_VERSION`] are treated as
+/// legacy, unframed entries and are returned unchanged. Returns `None` on
+/// a crc mismatch so the caller can turn it into a clean error (logged
+/// with the region id and index) instead of a bad decode panicking
+/// further down the read path.
+fn unframe_entry(value: &[u8]) -> Option<Vec<u8>> {
+    if value.first() != Some(&ENTRY_FRAME_VERSION) || value.len() < ENTRY_FRAME_HEADER_LEN {
+        return Some(value.to_vec());
+    }
+    let crc = u32::from_le_bytes(value[1..5].try_into().unwrap());
+    let payload = &value[ENTRY_FRAME_HEADER_LEN..];
+    if crc32c(payload) != crc {
+        return None;
+    }
+    Some(payload.to_vec())
+}
+
 impl RaftEngine for SkiplistEngine {
     type LogBatch = SkiplistWriteBatch;
 
@@ -20,19 +59,51 @@ impl RaftEngine for SkiplistEngine {
 
     fn sync(&self) -> Result<()> {
         box_try!(self.sync_wal());
+        if let Some(wal) = &self.wal {
+            box_try!(wal.sync());
+        }
         Ok(())
     }
 
     fn get_raft_state(&self, raft_group_id: u64) -> Result<Option<RaftLocalState>> {
         let key = keys::raft_state_key(raft_group_id);
-        let state = box_try!(self.get_msg_cf(CF_DEFAULT, &key));
-        Ok(state)
+        match box_try!(self.get_value(&key)) {
+            None => Ok(None),
+            Some(v) => {
+                let plain = match &self.encryption {
+                    Some(enc) => box_try!(enc.decrypt(raft_group_id, 0, &v)),
+                    None => v.to_vec(),
+                };
+                let mut state = RaftLocalState::default();
+                state.merge_from_bytes(&plain)?;
+                Ok(Some(state))
+            }
+        }
     }
 
     fn get_entry(&self, raft_group_id: u64, index: u64) -> Result<Option<Entry>> {
         let key = keys::raft_log_key(raft_group_id, index);
-        let entry = box_try!(self.get_msg_cf(CF_DEFAULT, &key));
-        Ok(entry)
+        match box_try!(self.get_value(&key)) {
+            None => Ok(None),
+            Some(v) => {
+                let v = match &self.encryption {
+                    Some(enc) => box_try!(enc.decrypt(raft_group_id, index, &v)),
+                    None => v.to_vec(),
+                };
+                match unframe_entry(&v) {
+                    Some(payload) => {
+                        let mut entry = Entry::default();
+                        entry.merge_from_bytes(&payload)?;
+                        Ok(Some(entry))
+                    }
+                    None => {
+                        error!("raft log entry crc mismatch, possible corruption";
+                            "region_id" => raft_group_id, "index" => index);
+                        Err(Error::Storage(StorageError::Unavailable))
+                    }
+                }
+            }
+        }
     }
 
     fn fetch_entries_to(
@@ -43,6 +114,16 @@ impl RaftEngine for SkiplistEngine {
         max_size: Option<usize>,
         buf: &mut Vec<Entry>,
     ) -> Result<usize> {
+        if let Some(range) = self.index_cache.get(region_id) {
+            if low < range.first || high > range.last + 1 {
+                // The cache is a conservative view of what's actually live, so
+                // this can only reject requests that really do reach past a
+                // compacted or not-yet-written index; no need to scan to find
+                // that out.
+                return Err(Error::Storage(StorageError::Unavailable));
+            }
+        }
+
         let (max_size, mut total_size, mut count) = (max_size.unwrap_or(usize::MAX), 0, 0);
 
         if high - low <= RAFT_LOG_MULTI_GET_CNT {
@@ -55,11 +136,27 @@ impl RaftEngine for SkiplistEngine {
                 match self.get_value(&key) {
                     Ok(None) => return Err(Error::Storage(StorageError::Unavailable)),
                     Ok(Some(v)) => {
+                        let v = match &self.encryption {
+                            Some(enc) => box_try!(enc.decrypt(region_id, i, &v)),
+                            None => v.to_vec(),
+                        };
+                        let payload = match unframe_entry(&v) {
+                            Some(p) => p,
+                            None => {
+                                error!("raft log entry crc mismatch, possible corruption";
+                                    "region_id" => region_id, "index" => i);
+                                return Err(Error::Storage(StorageError::Unavailable));
+                            }
+                        };
                         let mut entry = Entry::default();
-                        entry.merge_from_bytes(&v)?;
-                        assert_eq!(entry.get_index(), i);
+                        entry.merge_from_bytes(&payload)?;
+                        if entry.get_index() != i {
+                            error!("raft log entry index mismatch, possible corruption";
+                                "region_id" => region_id, "expected" => i, "actual" => entry.get_index());
+                            return Err(Error::Storage(StorageError::Unavailable));
+                        }
+                        total_size += payload.len();
                         buf.push(entry);
-                        total_size += v.len();
                         count += 1;
                     }
                     Err(e) => return Err(box_err!(e)),
@@ -75,9 +172,33 @@ impl RaftEngine for SkiplistEngine {
             &start_key,
             &end_key,
             true, // fill_cache
-            |_, value| {
+            |key, value| {
+                let decrypted;
+                let value = match &self.encryption {
+                    Some(enc) => {
+                        let index = keys::raft_log_index(key).unwrap_or(0);
+                        decrypted = match enc.decrypt(region_id, index, value) {
+                            Ok(v) => v,
+                            Err(e) => {
+                                error!("failed to decrypt raft log entry";
+                                    "region_id" => region_id, "err" => %e);
+                                return Ok(false);
+                            }
+                        };
+                        decrypted.as_slice()
+                    }
+                    None => value,
+                };
+                let payload = match unframe_entry(value) {
+                    Some(p) => p,
+                    None => {
+                        error!("raft log entry crc mismatch, possible corruption";
+                            "region_id" => region_id);
+                        return Ok(false);
+                    }
+                };
                 let mut entry = Entry::default();
-                entry.merge_from_bytes(value)?;
+                entry.merge_from_bytes(&payload)?;
 
                 if check_compacted {
                     if entry.get_index() != low {
@@ -85,13 +206,15 @@ impl RaftEngine for SkiplistEngine {
                         return Ok(false);
                     }
                     check_compacted = false;
-                } else {
-                    assert_eq!(entry.get_index(), next_index);
+                } else if entry.get_index() != next_index {
+                    error!("raft log entry index mismatch, possible corruption";
+                        "region_id" => region_id, "expected" => next_index, "actual" => entry.get_index());
+                    return Ok(false);
                 }
                 next_index += 1;
 
+                total_size += payload.len();
                 buf.push(entry);
-                total_size += value.len();
                 count += 1;
                 Ok(total_size < max_size)
             },
@@ -111,7 +234,14 @@ impl RaftEngine for SkiplistEngine {
         let bytes = batch.data_size();
         let mut opts = WriteOptions::default();
         opts.set_sync(sync_log);
-        box_try!(self.write_opt(batch, &opts));
+        if let Some(wal) = &self.wal {
+            wal.set_pending_sync(sync_log);
+        }
+        let res = self.write_opt(batch, &opts);
+        if let Some(wal) = &self.wal {
+            wal.set_pending_sync(false);
+        }
+        box_try!(res);
         batch.clear();
         Ok(bytes)
     }
@@ -153,6 +283,10 @@ impl RaftEngine for SkiplistEngine {
                 box_try!(batch.delete(&key));
             }
         }
+        if let Some(wal) = &self.wal {
+            wal.remove_group(raft_group_id);
+        }
+        self.index_cache.remove(raft_group_id);
         Ok(())
     }
 
@@ -168,7 +302,24 @@ impl RaftEngine for SkiplistEngine {
     }
 
     fn put_raft_state(&self, raft_group_id: u64, state: &RaftLocalState) -> Result<()> {
-        box_try!(self.put_msg(&keys::raft_state_key(raft_group_id), state));
+        let mut plain = Vec::new();
+        state.write_to_vec(&mut plain).unwrap();
+        let value = match &self.encryption {
+            Some(enc) => box_try!(enc.encrypt(raft_group_id, 0, &plain)),
+            None => plain,
+        };
+        // Unlike `consume`, there's no `sync_log` argument here for a
+        // caller to thread through, and no later batch commit that would
+        // otherwise flush this write - so this direct write always asks
+        // for a synchronous append.
+        if let Some(wal) = &self.wal {
+            wal.set_pending_sync(true);
+        }
+        let res = self.put(&keys::raft_state_key(raft_group_id), &value);
+        if let Some(wal) = &self.wal {
+            wal.set_pending_sync(false);
+        }
+        box_try!(res);
         Ok(())
     }
 
@@ -177,13 +328,20 @@ impl RaftEngine for SkiplistEngine {
             return Ok(0);
         }
         if from == 0 {
-            let start_key = keys::raft_log_key(raft_group_id, 0);
-            let prefix = keys::raft_log_prefix(raft_group_id);
-            match box_try!(self.seek(&start_key)) {
-                Some((k, _)) if k.starts_with(&prefix) => from = box_try!(keys::raft_log_index(&k)),
-                // No need to gc.
-                _ => return Ok(0),
-            }
+            from = match self.index_cache.get(raft_group_id) {
+                Some(range) => range.first,
+                None => {
+                    let start_key = keys::raft_log_key(raft_group_id, 0);
+                    let prefix = keys::raft_log_prefix(raft_group_id);
+                    match box_try!(self.seek(&start_key)) {
+                        Some((k, _)) if k.starts_with(&prefix) => {
+                            box_try!(keys::raft_log_index(&k))
+                        }
+                        // No need to gc.
+                        _ => return Ok(0),
+                    }
+                }
+            };
         }
 
         let mut raft_wb = self.write_batch_with_cap(MAX_DELETE_BATCH_SIZE);
@@ -201,6 +359,10 @@ impl RaftEngine for SkiplistEngine {
         if !Mutable::is_empty(&raft_wb) {
             self.write(&raft_wb).unwrap();
         }
+        if let Some(wal) = &self.wal {
+            wal.advance_first_index(raft_group_id, to);
+        }
+        self.index_cache.note_gc(raft_group_id, to);
         Ok((to - from) as usize)
     }
 
@@ -231,10 +393,17 @@ impl RaftLogBatch for SkiplistWriteBatch {
             let key = keys::raft_log_key(raft_group_id, index);
             self.delete(&key).unwrap();
         }
+        self.engine.index_cache.note_gc(raft_group_id, to);
     }
 
     fn put_raft_state(&mut self, raft_group_id: u64, state: &RaftLocalState) -> Result<()> {
-        box_try!(self.put_msg(&keys::raft_state_key(raft_group_id), state));
+        let mut plain = Vec::new();
+        state.write_to_vec(&mut plain).unwrap();
+        let value = match &self.engine.encryption {
+            Some(enc) => box_try!(enc.encrypt(raft_group_id, 0, &plain)),
+            None => plain,
+        };
+        box_try!(self.put(&keys::raft_state_key(raft_group_id), &value));
         Ok(())
     }
 
@@ -254,7 +423,12 @@ impl SkiplistWriteBatch {
             let key = keys::raft_log_key(raft_group_id, entry.get_index());
             ser_buf.clear();
             entry.write_to_vec(&mut ser_buf).unwrap();
-            box_try!(self.put(&key, &ser_buf));
+            let framed = frame_entry(&ser_buf);
+            let value = match &self.engine.encryption {
+                Some(enc) => box_try!(enc.encrypt(raft_group_id, entry.get_index(), &framed)),
+                None => framed,
+            };
+            box_try!(self.put(&key, &value));
         }
         Ok(())
     }
@@ -0,0 +1,519 @@
+// Copyright 2020 TiKV Project Authors. Licensed under Apache-2.0.
+
+//! A segmented, append-only write-ahead log that gives `SkiplistEngine`'s
+//! `RaftEngine` implementation real durability. `SkiplistEngine` itself is
+//! pure memory, so without this every raft log entry and `RaftLocalState`
+//! written through `consume`/`write_opt` would vanish on restart.
+//!
+//! Every mutation applied to the skiplist is first framed as
+//! `[u32 len][u32 crc32c][payload]` and appended to the active segment,
+//! fsync'd when the caller asked for `sync_log`. That intent is carried
+//! from `consume`/`put_raft_state` to the `append` call their write
+//! triggers via a thread-local flag rather than a field shared across every
+//! caller of this `Wal` - the engine is used concurrently from many
+//! threads, and a shared flag would let one thread's `consume` pick up (or
+//! clear) another's sync intent mid-write. Segments rotate once they grow
+//! past [`DEFAULT_SEGMENT_SIZE`] and are deleted once every raft group they
+//! mention has had its first index advanced past everything the segment
+//! holds for that group (driven by `gc`/`clean`) - unless the
+//! segment also holds a write this WAL can't attribute to a group/index at
+//! all (a `RaftLocalState`), in which case it's kept around indefinitely
+//! rather than risk reaping that key's only copy. On open,
+//! surviving segments are replayed in order to rebuild the skiplist; a torn
+//! tail in the last segment (a CRC mismatch at EOF) is expected after a
+//! crash and is treated as the end of the log, not an error. Opening an
+//! existing WAL reuses its trailing segment as the active one rather than
+//! always starting a fresh (empty) one, unless that segment has already hit
+//! the rotation threshold.
+
+use std::cell::Cell;
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::Ordering;
+use std::sync::Mutex;
+
+use crc32c::crc32c;
+use tikv_util::warn;
+
+use crate::engine::SkiplistEngine;
+
+/// Segments rotate once they reach this size.
+const DEFAULT_SEGMENT_SIZE: u64 = 64 * 1024 * 1024;
+
+const SEGMENT_EXT: &str = "wal";
+
+thread_local! {
+    /// Carries one thread's sync intent from `Wal::set_pending_sync` to the
+    /// `append` call(s) its write makes, without being visible to any other
+    /// thread sharing the same `Wal`.
+    static PENDING_SYNC: Cell<bool> = Cell::new(false);
+}
+
+#[derive(Debug)]
+enum WalOp {
+    Put {
+        cf: String,
+        key: Vec<u8>,
+        value: Vec<u8>,
+    },
+    Delete {
+        cf: String,
+        key: Vec<u8>,
+    },
+}
+
+fn write_bytes(buf: &mut Vec<u8>, bytes: &[u8]) {
+    buf.extend_from_slice(&(bytes.len() as u32).to_le_bytes());
+    buf.extend_from_slice(bytes);
+}
+
+fn read_bytes(buf: &[u8]) -> Option<(&[u8], &[u8])> {
+    if buf.len() < 4 {
+        return None;
+    }
+    let len = u32::from_le_bytes(buf[..4].try_into().ok()?) as usize;
+    let rest = &buf[4..];
+    if rest.len() < len {
+        return None;
+    }
+    Some((&rest[..len], &rest[len..]))
+}
+
+impl WalOp {
+    fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+        match self {
+            WalOp::Put { cf, key, value } => {
+                buf.push(0u8);
+                write_bytes(&mut buf, cf.as_bytes());
+                write_bytes(&mut buf, key);
+                write_bytes(&mut buf, value);
+            }
+            WalOp::Delete { cf, key } => {
+                buf.push(1u8);
+                write_bytes(&mut buf, cf.as_bytes());
+                write_bytes(&mut buf, key);
+            }
+        }
+        buf
+    }
+
+    fn decode(buf: &[u8]) -> Option<WalOp> {
+        let (tag, buf) = (*buf.first()?, &buf[1..]);
+        match tag {
+            0 => {
+                let (cf, rest) = read_bytes(buf)?;
+                let (key, rest) = read_bytes(rest)?;
+                let (value, _) = read_bytes(rest)?;
+                Some(WalOp::Put {
+                    cf: String::from_utf8(cf.to_vec()).ok()?,
+                    key: key.to_vec(),
+                    value: value.to_vec(),
+                })
+            }
+            1 => {
+                let (cf, rest) = read_bytes(buf)?;
+                let (key, _) = read_bytes(rest)?;
+                Some(WalOp::Delete {
+                    cf: String::from_utf8(cf.to_vec()).ok()?,
+                    key: key.to_vec(),
+                })
+            }
+            _ => None,
+        }
+    }
+
+    /// The raft group this op belongs to, if it is a raft log entry. Used
+    /// to track which groups keep a segment alive; anything else (raft
+    /// state keys, or a caller writing outside the raft log keyspace) pins
+    /// the segment indefinitely, which is conservative and safe.
+    fn group_and_index(&self) -> Option<(u64, u64)> {
+        let key = match self {
+            WalOp::Put { key, .. } | WalOp::Delete { key, .. } => key,
+        };
+        let index = keys::raft_log_index(key).ok()?;
+        let group = raft_log_group_id(key)?;
+        Some((group, index))
+    }
+}
+
+/// Recovers the raft group id from a `keys::raft_log_key(region_id, _)`,
+/// which lays the region id out as a big-endian `u64` right after the two
+/// fixed prefix bytes (`LOCAL_PREFIX`, `REGION_RAFT_PREFIX`).
+fn raft_log_group_id(key: &[u8]) -> Option<u64> {
+    let region_id_bytes = key.get(2..10)?;
+    Some(u64::from_be_bytes(region_id_bytes.try_into().ok()?))
+}
+
+#[derive(Debug)]
+struct Segment {
+    id: u64,
+    file: File,
+    size: u64,
+    /// Highest index this segment has recorded for each raft group it
+    /// mentions; the segment cannot be deleted until every group here has
+    /// had its first live index advance past the recorded value.
+    group_max_index: HashMap<u64, u64>,
+    /// Set once this segment has recorded a write whose key `group_and_index`
+    /// can't resolve to a `(group, index)` - a `RaftLocalState` or anything
+    /// else outside the raft log keyspace. Such writes aren't superseded in
+    /// any way this WAL tracks, so the only safe thing to do is keep the
+    /// whole segment around indefinitely rather than risk reaping a key's
+    /// only copy.
+    has_untracked: bool,
+}
+
+#[derive(Debug)]
+struct WalState {
+    dir: PathBuf,
+    segments: Vec<Segment>,
+    group_first_index: HashMap<u64, u64>,
+}
+
+/// A segmented, append-only WAL backing a single `SkiplistEngine`.
+#[derive(Debug)]
+pub struct Wal {
+    state: Mutex<WalState>,
+}
+
+fn segment_path(dir: &Path, id: u64) -> PathBuf {
+    dir.join(format!("{:016}.{}", id, SEGMENT_EXT))
+}
+
+fn list_segment_ids(dir: &Path) -> io::Result<Vec<u64>> {
+    let mut ids = vec![];
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let path = entry.path();
+        if path.extension().and_then(|e| e.to_str()) != Some(SEGMENT_EXT) {
+            continue;
+        }
+        if let Some(stem) = path.file_stem().and_then(|s| s.to_str()) {
+            if let Ok(id) = stem.parse::<u64>() {
+                ids.push(id);
+            }
+        }
+    }
+    ids.sort_unstable();
+    Ok(ids)
+}
+
+impl Wal {
+    /// Opens (creating if necessary) the WAL rooted at `dir`. Existing
+    /// segments are kept as-is. The trailing segment becomes the active one
+    /// and keeps accepting appends as-is, unless it's already at or past
+    /// [`DEFAULT_SEGMENT_SIZE`] (or there are no segments yet), in which
+    /// case a fresh one is started - otherwise every restart would leave
+    /// behind another empty segment file that only `gc`/`clean` eventually
+    /// cleans up.
+    pub fn open(dir: impl Into<PathBuf>) -> io::Result<Wal> {
+        let dir = dir.into();
+        fs::create_dir_all(&dir)?;
+        let mut segments = vec![];
+        for id in list_segment_ids(&dir)? {
+            let file = OpenOptions::new().read(true).append(true).open(segment_path(&dir, id))?;
+            let size = file.metadata()?.len();
+            segments.push(Segment {
+                id,
+                file,
+                size,
+                group_max_index: HashMap::new(),
+                has_untracked: false,
+            });
+        }
+        let needs_fresh_segment = match segments.last() {
+            Some(last) => last.size >= DEFAULT_SEGMENT_SIZE,
+            None => true,
+        };
+        if needs_fresh_segment {
+            let next_id = segments.last().map(|s| s.id + 1).unwrap_or(0);
+            segments.push(Segment {
+                id: next_id,
+                file: OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(segment_path(&dir, next_id))?,
+                size: 0,
+                group_max_index: HashMap::new(),
+                has_untracked: false,
+            });
+        }
+        Ok(Wal {
+            state: Mutex::new(WalState {
+                dir,
+                segments,
+                group_first_index: HashMap::new(),
+            }),
+        })
+    }
+
+    /// Replays every surviving record into `engine`, rebuilding the
+    /// skiplist and this WAL's own group/index bookkeeping. A CRC mismatch
+    /// or a truncated record is treated as a torn write at the tail of the
+    /// log and simply ends replay for that segment rather than failing.
+    pub fn replay(&self, engine: &SkiplistEngine) -> io::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        let n = state.segments.len();
+        for i in 0..n {
+            let path = segment_path(&state.dir, state.segments[i].id);
+            let mut buf = vec![];
+            File::open(&path)?.read_to_end(&mut buf)?;
+            let mut offset = 0;
+            while offset + 8 <= buf.len() {
+                let len = u32::from_le_bytes(buf[offset..offset + 4].try_into().unwrap()) as usize;
+                let crc = u32::from_le_bytes(buf[offset + 4..offset + 8].try_into().unwrap());
+                let payload_start = offset + 8;
+                let payload_end = payload_start + len;
+                if payload_end > buf.len() {
+                    break; // torn tail, expected after a crash
+                }
+                let payload = &buf[payload_start..payload_end];
+                if crc32c(payload) != crc {
+                    break; // corrupted tail record, stop replaying this segment
+                }
+                if let Some(op) = WalOp::decode(payload) {
+                    match op.group_and_index() {
+                        Some((group, index)) => {
+                            state.segments[i]
+                                .group_max_index
+                                .entry(group)
+                                .and_modify(|m| *m = (*m).max(index))
+                                .or_insert(index);
+                        }
+                        None => state.segments[i].has_untracked = true,
+                    }
+                    apply_op(engine, &op);
+                }
+                offset = payload_end;
+            }
+        }
+        Ok(())
+    }
+
+    /// Sets this thread's sync intent for the next `append`(s) it makes
+    /// through this `Wal`, e.g. around a `consume`/`put_raft_state` call.
+    /// Scoped to the calling thread so concurrent callers on other threads
+    /// can't observe or clear each other's intent.
+    pub fn set_pending_sync(&self, sync: bool) {
+        PENDING_SYNC.with(|cell| cell.set(sync));
+    }
+
+    /// Fsyncs every segment that might still hold data written without a
+    /// synchronous `append`, not just the active one - a prior write that
+    /// landed in a since-rotated segment with `sync_log` false would
+    /// otherwise never get flushed by a later explicit `sync()` call.
+    pub fn sync(&self) -> io::Result<()> {
+        let state = self.state.lock().unwrap();
+        for segment in &state.segments {
+            segment.file.sync_data()?;
+        }
+        Ok(())
+    }
+
+    fn append(&self, op: WalOp) -> io::Result<()> {
+        let payload = op.encode();
+        let group_index = op.group_and_index();
+        let crc = crc32c(&payload);
+        let mut record = Vec::with_capacity(8 + payload.len());
+        record.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+        record.extend_from_slice(&crc.to_le_bytes());
+        record.extend_from_slice(&payload);
+
+        let sync = PENDING_SYNC.with(|cell| cell.get());
+        let mut state = self.state.lock().unwrap();
+        {
+            let active = state.segments.last_mut().unwrap();
+            active.file.write_all(&record)?;
+            if sync {
+                active.file.sync_data()?;
+            }
+            active.size += record.len() as u64;
+            match group_index {
+                Some((group, index)) => {
+                    active
+                        .group_max_index
+                        .entry(group)
+                        .and_modify(|m| *m = (*m).max(index))
+                        .or_insert(index);
+                }
+                None => active.has_untracked = true,
+            }
+        }
+        if state.segments.last().unwrap().size >= DEFAULT_SEGMENT_SIZE {
+            self.rotate(&mut state)?;
+        }
+        Ok(())
+    }
+
+    pub fn append_put(&self, cf: &str, key: &[u8], value: &[u8]) -> io::Result<()> {
+        self.append(WalOp::Put {
+            cf: cf.to_owned(),
+            key: key.to_vec(),
+            value: value.to_vec(),
+        })
+    }
+
+    pub fn append_delete(&self, cf: &str, key: &[u8]) -> io::Result<()> {
+        self.append(WalOp::Delete {
+            cf: cf.to_owned(),
+            key: key.to_vec(),
+        })
+    }
+
+    fn rotate(&self, state: &mut WalState) -> io::Result<()> {
+        let next_id = state.segments.last().unwrap().id + 1;
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(segment_path(&state.dir, next_id))?;
+        state.segments.push(Segment {
+            id: next_id,
+            file,
+            size: 0,
+            group_max_index: HashMap::new(),
+            has_untracked: false,
+        });
+        Ok(())
+    }
+
+    /// Called once a raft group's first live index advances (via `gc`), so
+    /// segments that no longer hold any live entry for any group can be
+    /// dropped.
+    pub fn advance_first_index(&self, group: u64, first_index: u64) {
+        let mut state = self.state.lock().unwrap();
+        state.group_first_index.insert(group, first_index);
+        self.collect_obsolete_segments(&mut state);
+    }
+
+    /// Called when a group is removed entirely (`clean`), so segments that
+    /// only held that group's now-gone entries can be dropped.
+    pub fn remove_group(&self, group: u64) {
+        let mut state = self.state.lock().unwrap();
+        state.group_first_index.insert(group, u64::MAX);
+        self.collect_obsolete_segments(&mut state);
+    }
+
+    fn collect_obsolete_segments(&self, state: &mut WalState) {
+        let first_index = state.group_first_index.clone();
+        let dir = state.dir.clone();
+        let active_id = state.segments.last().unwrap().id;
+        let mut kept = Vec::with_capacity(state.segments.len());
+        for segment in state.segments.drain(..) {
+            // A segment holding an untracked write (e.g. a `RaftLocalState`)
+            // is kept forever: those keys aren't tracked per-group, so there
+            // is no way to tell whether this is still the only copy.
+            let still_needed = segment.id == active_id
+                || segment.has_untracked
+                || segment.group_max_index.iter().any(|(group, max_index)| {
+                    first_index.get(group).copied().unwrap_or(0) <= *max_index
+                });
+            if still_needed {
+                kept.push(segment);
+            } else {
+                let path = segment_path(&dir, segment.id);
+                if let Err(e) = fs::remove_file(&path) {
+                    warn!("failed to remove obsolete raft engine WAL segment";
+                        "path" => ?path, "err" => %e);
+                }
+            }
+        }
+        state.segments = kept;
+    }
+}
+
+fn apply_op(engine: &SkiplistEngine, op: &WalOp) {
+    match op {
+        WalOp::Put { cf, key, value } => {
+            if let Ok(map) = engine.get_cf_engine(cf) {
+                if map.get(key).is_none() {
+                    engine
+                        .total_bytes
+                        .fetch_add(key.len() + value.len(), Ordering::Relaxed);
+                }
+                map.insert(key.clone(), value.clone());
+                engine.index_cache.note_put(key);
+                if let Some(eviction) = &engine.eviction {
+                    eviction.note_write(cf, key);
+                }
+            }
+        }
+        WalOp::Delete { cf, key } => {
+            if let Ok(map) = engine.get_cf_engine(cf) {
+                if let Some(e) = map.remove(key) {
+                    engine
+                        .total_bytes
+                        .fetch_sub(e.key().len() + e.value().len(), Ordering::Relaxed);
+                }
+                // Mirrors `SkiplistEngine::delete_cf`: `gc`/`cut_logs`
+                // replay as a run of per-key deletes and don't persist
+                // their own `note_gc` call as a WAL record, so invalidating
+                // the whole group here on every key would leave the cache
+                // permanently empty for it after replay. The range rebuilt by
+                // the `Put` arm's `note_put` above is only ever widened, so it
+                // can still cover indices that a later replayed delete removed.
+                if let Some(eviction) = &engine.eviction {
+                    eviction.note_removed(cf, key);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use engine_traits::{Peekable, CF_DEFAULT};
+    use tempfile::Builder;
+
+    use super::*;
+    use crate::engine::SkiplistEngineBuilder;
+
+    #[test]
+    fn test_append_and_replay_rebuilds_skiplist() {
+        let dir = Builder::new().prefix("engine_skiplist_wal_test").tempdir().unwrap();
+        let wal = Wal::open(dir.path()).unwrap();
+        wal.append_put(CF_DEFAULT, b"k1", b"v1").unwrap();
+        wal.append_put(CF_DEFAULT, b"k2", b"v2").unwrap();
+        wal.append_delete(CF_DEFAULT, b"k1").unwrap();
+        drop(wal);
+
+        let engine = SkiplistEngineBuilder::new().wal_dir(dir.path()).build();
+        assert!(engine.get_value(b"k1").unwrap().is_none());
+        assert_eq!(engine.get_value(b"k2").unwrap().unwrap().to_vec(), b"v2".to_vec());
+    }
+
+    #[test]
+    fn test_replay_tolerates_torn_tail() {
+        let dir = Builder::new().prefix("engine_skiplist_wal_test").tempdir().unwrap();
+        let wal = Wal::open(dir.path()).unwrap();
+        wal.append_put(CF_DEFAULT, b"k1", b"v1").unwrap();
+        drop(wal);
+
+        // Simulate a crash mid-write: a few extra bytes that don't form a
+        // complete, checksum-valid record.
+        let segment = segment_path(dir.path(), 0);
+        let mut file = OpenOptions::new().append(true).open(&segment).unwrap();
+        file.write_all(&[1, 2, 3]).unwrap();
+        drop(file);
+
+        let engine = SkiplistEngineBuilder::new().wal_dir(dir.path()).build();
+        assert_eq!(engine.get_value(b"k1").unwrap().unwrap().to_vec(), b"v1".to_vec());
+    }
+
+    #[test]
+    fn test_open_reuses_trailing_segment() {
+        let dir = Builder::new().prefix("engine_skiplist_wal_test").tempdir().unwrap();
+        {
+            let wal = Wal::open(dir.path()).unwrap();
+            wal.append_put(CF_DEFAULT, b"k1", b"v1").unwrap();
+        }
+        assert_eq!(list_segment_ids(dir.path()).unwrap(), vec![0]);
+
+        // Reopening without having rotated shouldn't leave behind another
+        // empty segment file.
+        let _wal = Wal::open(dir.path()).unwrap();
+        assert_eq!(list_segment_ids(dir.path()).unwrap(), vec![0]);
+    }
+}